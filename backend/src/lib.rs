@@ -1,4 +1,30 @@
-use spacetimedb::{table, reducer, Table, ReducerContext, Timestamp};
+use spacetimedb::{table, reducer, Identity, ScheduleAt, SpacetimeType, Table, ReducerContext, TimeDuration, Timestamp};
+
+// How checkpoints must be claimed. Set once at creation and enforced by
+// `scan_checkpoint`/`scan_checkpoint_at`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, SpacetimeType)]
+pub enum GameMode {
+    // Checkpoints must be scanned in `order_index` order.
+    Sequential,
+    // Any active checkpoint can be scanned once, in any order.
+    FreeOrder,
+    // Like FreeOrder, but each checkpoint's `points` accumulate onto the
+    // player's `PlayerGame.points_total`.
+    Scored,
+}
+
+// A player's standing in a game. Enforced by reducers rather than treated
+// as free-text metadata.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, SpacetimeType)]
+pub enum PlayerRole {
+    // The game's host. Can register checkpoints; implied by `Game.host`,
+    // not requested through `join_game`.
+    Organizer,
+    // Scans checkpoints and appears on the leaderboard.
+    Player,
+    // Can follow players' progress but cannot scan checkpoints.
+    Spectator,
+}
 
 #[table(name = game)]
 #[derive(Clone)]
@@ -10,6 +36,98 @@ pub struct Game {
     pub name: String,
     pub created_at: Timestamp,
     pub is_active: bool,
+    // When the game's timer runs out. None means the game has no timer, so
+    // `expire_tick` never deactivates it.
+    pub ends_at: Option<Timestamp>,
+    // The identity that created the game. Only the host may register
+    // checkpoints or otherwise mutate this game's setup.
+    pub host: Identity,
+    // Caps how many players may join via `join_game`. None means unlimited.
+    pub max_players: Option<u32>,
+    pub mode: GameMode,
+}
+
+// Mirrors the shape of errors a client needs to distinguish when trying to
+// join a game, rather than collapsing them into one opaque string.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JoinGameError {
+    GameNotFound,
+    GameNotActive,
+    GameFull,
+    NameTaken,
+}
+
+impl std::fmt::Display for JoinGameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JoinGameError::GameNotFound => write!(f, "game not found"),
+            JoinGameError::GameNotActive => write!(f, "game is not active"),
+            JoinGameError::GameFull => write!(f, "game is full"),
+            JoinGameError::NameTaken => write!(f, "name already taken"),
+        }
+    }
+}
+
+// Only the game's host may perform organizer-only actions like registering
+// checkpoints.
+fn require_host(ctx: &ReducerContext, game: &Game) -> Result<(), String> {
+    if game.host != ctx.sender {
+        return Err("not organizer".to_string());
+    }
+    Ok(())
+}
+
+// How often the scheduled reducer sweeps for games whose timer has elapsed.
+const TICK_INTERVAL_SECS: u64 = 10;
+
+// Runs every `TICK_INTERVAL_SECS` seconds to deactivate timed-out games, so
+// hunts expire without an external cron.
+#[table(name = scheduled_task, scheduled(expire_tick))]
+pub struct ScheduledTask {
+    #[primary_key]
+    #[auto_inc]
+    pub scheduled_id: u64,
+    pub scheduled_at: ScheduleAt,
+}
+
+#[reducer(init)]
+pub fn init(ctx: &ReducerContext) {
+    ctx.db.scheduled_task().insert(ScheduledTask {
+        scheduled_id: 0,
+        scheduled_at: TimeDuration::from_duration(std::time::Duration::from_secs(TICK_INTERVAL_SECS)).into(),
+    });
+}
+
+#[reducer]
+pub fn expire_tick(ctx: &ReducerContext, _task: ScheduledTask) -> Result<(), String> {
+    // Only the scheduler may invoke this - reject direct calls from clients.
+    if ctx.sender != ctx.identity() {
+        return Err("expire_tick may only be invoked by the scheduler".to_string());
+    }
+
+    let expired_games: Vec<Game> = ctx.db.game().iter()
+        .filter(|g| g.is_active && g.ends_at.map_or(false, |ends_at| ctx.timestamp >= ends_at))
+        .collect();
+
+    for game in expired_games {
+        let expired = Game { is_active: false, ..game.clone() };
+        ctx.db.game().delete(game);
+        ctx.db.game().insert(expired);
+    }
+
+    let expired_checkpoints: Vec<Checkpoint> = ctx.db.checkpoint().iter()
+        .filter(|cp| cp.is_active && cp.expires_at.map_or(false, |expires_at| ctx.timestamp >= expires_at))
+        .collect();
+
+    for checkpoint in expired_checkpoints {
+        let checkpoint_id = checkpoint.checkpoint_id;
+        let expired = Checkpoint { is_active: false, ..checkpoint.clone() };
+        ctx.db.checkpoint().delete(checkpoint);
+        ctx.db.checkpoint().try_insert(expired).ok();
+        log::info!("Deactivated expired checkpoint: {}", checkpoint_id);
+    }
+
+    Ok(())
 }
 
 #[table(name = player)]
@@ -32,6 +150,41 @@ pub struct PlayerGame {
     pub checkpoints_scanned: u32,
     pub last_scan_at: Option<Timestamp>,
     pub next_required: u32,
+    // Set once `next_required` exceeds the game's checkpoint count.
+    pub finished_at: Option<Timestamp>,
+    // 1-based placement among players who have finished this game.
+    pub finish_rank: Option<u32>,
+    // Sum of claimed checkpoints' `points`. Only meaningful in `Scored` games.
+    pub points_total: u32,
+    pub role: PlayerRole,
+}
+
+// Ranks each game's players by checkpoints_scanned (by points_total in
+// `Scored` games), tie-broken by last_scan_at, recomputed after every scan
+// so clients can subscribe to live standings.
+#[table(name = leaderboard, public)]
+#[derive(Clone)]
+pub struct LeaderboardEntry {
+    #[primary_key]
+    pub player_game_id: u64,
+    pub game_id: u64,
+    pub player_id: u64,
+    pub rank: u32,
+    pub checkpoints_scanned: u32,
+    pub last_scan_at: Option<Timestamp>,
+    // Mirrors `PlayerGame.points_total`. Only meaningful in `Scored` games.
+    pub points_total: u32,
+}
+
+// Lets a spectator or teammate subscribe to another player's progress
+// without being an active racer themselves.
+#[table(name = follow)]
+#[derive(Clone)]
+pub struct Follow {
+    #[primary_key]
+    pub follow_id: u64,
+    pub follower_player_id: u64,
+    pub target_player_id: u64,
 }
 
 #[table(name = checkpoint)]
@@ -44,6 +197,36 @@ pub struct Checkpoint {
     pub location_name: String,
     pub order_index: u32,
     pub created_at: Timestamp,
+    pub lat: Option<f64>,
+    pub lon: Option<f64>,
+    pub accuracy_m: Option<f64>,
+    // Point value awarded for scanning this checkpoint in a `Scored` game.
+    pub points: Option<u32>,
+    pub is_active: bool,
+    // When this checkpoint's activation window runs out. None means the
+    // checkpoint stays active for the rest of the game.
+    pub expires_at: Option<Timestamp>,
+}
+
+// Binds a caller's identity to a player_id, so reducers can resolve "who is
+// calling" from ctx.sender instead of trusting a client-supplied player_id.
+#[table(name = session)]
+#[derive(Clone)]
+pub struct Session {
+    #[primary_key]
+    pub identity: Identity,
+    pub player_id: u64,
+}
+
+// One-time codes handed out so a second device can bind to an already-
+// registered player via `link_device`.
+#[table(name = link_code)]
+#[derive(Clone)]
+pub struct LinkCode {
+    #[primary_key]
+    pub token: String,
+    pub player_id: u64,
+    pub used: bool,
 }
 
 #[table(name = scan_event)]
@@ -56,6 +239,52 @@ pub struct ScanEvent {
     pub checkpoint_id: u64,
     pub scanned_at: Timestamp,
     pub client_token: String,
+    // Reported position at scan time, kept for anti-cheat audit.
+    pub player_lat: Option<f64>,
+    pub player_lon: Option<f64>,
+    pub player_accuracy_m: Option<f64>,
+}
+
+// Extra slack added on top of the checkpoint's and player's reported GPS
+// accuracy before a scan is rejected as "too far away".
+const GEOFENCE_SLACK_M: f64 = 15.0;
+
+// Great-circle distance between two lat/lon points, in meters.
+fn haversine_distance_m(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    const EARTH_RADIUS_M: f64 = 6_371_000.0;
+    let phi1 = lat1.to_radians();
+    let phi2 = lat2.to_radians();
+    let delta_phi = (lat2 - lat1).to_radians();
+    let delta_lambda = (lon2 - lon1).to_radians();
+
+    let a = (delta_phi / 2.0).sin().powi(2)
+        + phi1.cos() * phi2.cos() * (delta_lambda / 2.0).sin().powi(2);
+    let c = 2.0 * a.sqrt().atan2((1.0 - a).sqrt());
+
+    EARTH_RADIUS_M * c
+}
+
+#[cfg(test)]
+mod haversine_tests {
+    use super::haversine_distance_m;
+
+    #[test]
+    fn same_point_is_zero_distance() {
+        assert_eq!(haversine_distance_m(40.0, -75.0, 40.0, -75.0), 0.0);
+    }
+
+    #[test]
+    fn one_degree_of_latitude_is_about_111km() {
+        let distance_m = haversine_distance_m(0.0, 0.0, 1.0, 0.0);
+        assert!((distance_m - 111_195.0).abs() < 1_000.0, "got {distance_m}");
+    }
+
+    #[test]
+    fn is_symmetric() {
+        let a_to_b = haversine_distance_m(40.0, -75.0, 41.0, -74.0);
+        let b_to_a = haversine_distance_m(41.0, -74.0, 40.0, -75.0);
+        assert_eq!(a_to_b, b_to_a);
+    }
 }
 
 fn get_next_game_id(ctx: &ReducerContext) -> u64 {
@@ -79,16 +308,137 @@ fn get_next_scan_id(ctx: &ReducerContext) -> u64 {
     ctx.db.scan_event().iter().count() as u64 + 1
 }
 
+fn get_next_follow_id(ctx: &ReducerContext) -> u64 {
+    ctx.db.follow().iter().count() as u64 + 1
+}
+
+fn checkpoint_count(ctx: &ReducerContext, game_id: u64) -> u32 {
+    ctx.db.checkpoint().iter().filter(|cp| cp.game_id == game_id).count() as u32
+}
+
+// Recompute the leaderboard for a game: rank by checkpoints_scanned
+// descending (points_total in `Scored` games), ties broken by whoever last
+// scanned earlier.
+fn recompute_leaderboard(ctx: &ReducerContext, game_id: u64) {
+    let mode = ctx.db.game().game_id().find(&game_id).map(|g| g.mode);
+
+    let mut standings: Vec<PlayerGame> = ctx.db.player_game().iter()
+        .filter(|pg| pg.game_id == game_id)
+        .collect();
+
+    // Scored games rank by points, not raw checkpoint count, so point
+    // values actually affect standings.
+    if mode == Some(GameMode::Scored) {
+        standings.sort_by(|a, b| {
+            b.points_total.cmp(&a.points_total)
+                .then_with(|| a.last_scan_at.cmp(&b.last_scan_at))
+        });
+    } else {
+        standings.sort_by(|a, b| {
+            b.checkpoints_scanned.cmp(&a.checkpoints_scanned)
+                .then_with(|| a.last_scan_at.cmp(&b.last_scan_at))
+        });
+    }
+
+    for (i, pg) in standings.into_iter().enumerate() {
+        let entry = LeaderboardEntry {
+            player_game_id: pg.player_game_id,
+            game_id: pg.game_id,
+            player_id: pg.player_id,
+            rank: i as u32 + 1,
+            checkpoints_scanned: pg.checkpoints_scanned,
+            last_scan_at: pg.last_scan_at,
+            points_total: pg.points_total,
+        };
+
+        if ctx.db.leaderboard().player_game_id().find(&pg.player_game_id).is_some() {
+            ctx.db.leaderboard().player_game_id().delete(&pg.player_game_id);
+        }
+        ctx.db.leaderboard().try_insert(entry).ok();
+    }
+}
+
+// True once `next_required` (the checkpoint a player would scan next) has
+// advanced past the game's last checkpoint, i.e. every checkpoint has been
+// scanned.
+fn has_completed_all_checkpoints(next_required: u32, checkpoint_count: u32) -> bool {
+    next_required > checkpoint_count
+}
+
+// Mark a player's game as finished the first time their progress completes
+// the checkpoint sequence, stamping their placement among other finishers.
+fn mark_finished_if_complete(ctx: &ReducerContext, player_game: &PlayerGame) -> PlayerGame {
+    let total_checkpoints = checkpoint_count(ctx, player_game.game_id);
+    if player_game.finished_at.is_some() || !has_completed_all_checkpoints(player_game.next_required, total_checkpoints) {
+        return player_game.clone();
+    }
+
+    let finish_rank = ctx.db.player_game().iter()
+        .filter(|pg| pg.game_id == player_game.game_id && pg.finished_at.is_some())
+        .count() as u32 + 1;
+
+    PlayerGame {
+        finished_at: Some(ctx.timestamp),
+        finish_rank: Some(finish_rank),
+        ..player_game.clone()
+    }
+}
+
+#[cfg(test)]
+mod finish_tests {
+    use super::has_completed_all_checkpoints;
+
+    #[test]
+    fn not_finished_before_last_checkpoint() {
+        assert!(!has_completed_all_checkpoints(2, 3));
+    }
+
+    #[test]
+    fn finished_right_after_last_checkpoint() {
+        assert!(has_completed_all_checkpoints(4, 3));
+    }
+
+    #[test]
+    fn game_with_no_checkpoints_is_immediately_complete() {
+        assert!(has_completed_all_checkpoints(1, 0));
+    }
+}
+
 fn generate_unique_code(ctx: &ReducerContext) -> String {
     // Simple approach: use game count + prefix to ensure uniqueness
     let count = ctx.db.game().iter().count();
     format!("GAME{:04}", count + 1)
 }
 
+fn generate_link_token(ctx: &ReducerContext) -> String {
+    let count = ctx.db.link_code().iter().count();
+    format!("LINK{:06}", count + 1)
+}
+
+// Resolve the calling identity to the player_id it's bound to via `session`.
+fn resolve_player_id(ctx: &ReducerContext) -> Result<u64, String> {
+    ctx.db.session().iter()
+        .find(|s| s.identity == ctx.sender)
+        .map(|s| s.player_id)
+        .ok_or_else(|| "not joined".to_string())
+}
+
+// Bind the caller's identity to `player_id`, replacing any prior binding.
+fn bind_session(ctx: &ReducerContext, player_id: u64) {
+    if let Some(existing) = ctx.db.session().iter().find(|s| s.identity == ctx.sender) {
+        ctx.db.session().delete(existing);
+    }
+    ctx.db.session().insert(Session {
+        identity: ctx.sender,
+        player_id,
+    });
+}
+
 #[reducer]
-pub fn create_game(ctx: &ReducerContext, name: String) {
+pub fn create_game(ctx: &ReducerContext, name: String, mode: GameMode, duration_secs: Option<u64>, max_players: Option<u32>) {
     let game_id = get_next_game_id(ctx);
     let code = generate_unique_code(ctx);
+    let ends_at = duration_secs.map(|secs| ctx.timestamp + TimeDuration::from_duration(std::time::Duration::from_secs(secs)));
 
     let game = Game {
         game_id,
@@ -96,11 +446,17 @@ pub fn create_game(ctx: &ReducerContext, name: String) {
         name,
         created_at: ctx.timestamp,
         is_active: true,
+        ends_at,
+        host: ctx.sender,
+        max_players,
+        mode,
     };
 
     ctx.db.game().try_insert(game).ok();
 }
 
+// Register a checkpoint for this game. Organizer (host) only. `points` is
+// only meaningful in `Scored` games.
 #[reducer]
 pub fn register_checkpoint(
     ctx: &ReducerContext,
@@ -108,24 +464,37 @@ pub fn register_checkpoint(
     nfc_uid: String,
     location_name: String,
     order_index: u32,
-) {
+    points: Option<u32>,
+    lat: Option<f64>,
+    lon: Option<f64>,
+    accuracy_m: Option<f64>,
+    active_secs: Option<u64>,
+) -> Result<(), String> {
     if order_index == 0 {
-        return;
+        return Err("order_index must be nonzero".to_string());
     }
-    
-    let game = match ctx.db.game().iter().find(|g| g.code == game_code) {
-        Some(g) => g,
-        None => return,
-    };
-    
+
+    let game = ctx.db.game().iter().find(|g| g.code == game_code)
+        .ok_or("Game not found")?;
+
+    require_host(ctx, &game)?;
+
     // Check for duplicates
     for checkpoint in ctx.db.checkpoint().iter() {
-        if checkpoint.game_id == game.game_id && 
+        if checkpoint.game_id == game.game_id &&
            (checkpoint.nfc_uid == nfc_uid || checkpoint.order_index == order_index) {
-            return;
+            return Err("Checkpoint already registered".to_string());
         }
     }
-    
+
+    // lat/lon must be set together so scan_checkpoint_at's geofence check
+    // never sees a half-populated location.
+    if lat.is_some() != lon.is_some() {
+        return Err("lat and lon must be provided together".to_string());
+    }
+
+    let expires_at = active_secs.map(|secs| ctx.timestamp + TimeDuration::from_duration(std::time::Duration::from_secs(secs)));
+
     let checkpoint = Checkpoint {
         checkpoint_id: get_next_checkpoint_id(ctx),
         game_id: game.game_id,
@@ -133,9 +502,16 @@ pub fn register_checkpoint(
         location_name,
         order_index,
         created_at: ctx.timestamp,
+        lat,
+        lon,
+        accuracy_m,
+        points,
+        is_active: true,
+        expires_at,
     };
-    
-    ctx.db.checkpoint().try_insert(checkpoint).ok();
+
+    ctx.db.checkpoint().try_insert(checkpoint).map_err(|_| "Failed to register checkpoint".to_string())?;
+    Ok(())
 }
 
 #[reducer]
@@ -143,84 +519,345 @@ pub fn join_game(
     ctx: &ReducerContext,
     game_code: String,
     display_name: String,
-) {
-    let game = match ctx.db.game().iter().find(|g| g.code == game_code) {
-        Some(g) => g,
-        None => return,
+    role: Option<PlayerRole>,
+) -> Result<(), JoinGameError> {
+    // Organizer is implied by `Game.host`, not requested; anyone asking for
+    // it here just joins as a regular player.
+    let role = match role {
+        Some(PlayerRole::Organizer) | None => PlayerRole::Player,
+        Some(other) => other,
     };
-    
+
+    let game = ctx.db.game().iter().find(|g| g.code == game_code)
+        .ok_or(JoinGameError::GameNotFound)?;
+
     if !game.is_active {
-        return;
+        return Err(JoinGameError::GameNotActive);
     }
-    
-    let player_id = get_next_player_id(ctx);
-    let player = Player {
-        player_id,
-        display_name,
-        created_at: ctx.timestamp,
+
+    // A caller who already has a session-bound player may be rejoining this
+    // game (e.g. after a disconnect); reuse that player/player_game instead
+    // of minting a fresh one, and exclude it from the name-collision check
+    // below.
+    let existing_player_id = ctx.db.session().iter()
+        .find(|s| s.identity == ctx.sender)
+        .map(|s| s.player_id);
+    let existing_player_game = existing_player_id.and_then(|player_id| {
+        ctx.db.player_game().iter()
+            .find(|pg| pg.game_id == game.game_id && pg.player_id == player_id)
+    });
+
+    let name_taken = ctx.db.player_game().iter()
+        .filter(|pg| pg.game_id == game.game_id && Some(pg.player_id) != existing_player_id)
+        .any(|pg| {
+            ctx.db.player().player_id().find(&pg.player_id)
+                .map_or(false, |p| p.display_name == display_name)
+        });
+    if name_taken {
+        return Err(JoinGameError::NameTaken);
+    }
+
+    if existing_player_game.is_none() {
+        if let Some(max_players) = game.max_players {
+            let current_players = ctx.db.player_game().iter()
+                .filter(|pg| pg.game_id == game.game_id)
+                .count() as u32;
+            if current_players >= max_players {
+                return Err(JoinGameError::GameFull);
+            }
+        }
+    }
+
+    let player_id = match existing_player_id {
+        Some(player_id) => {
+            if let Some(existing) = ctx.db.player().player_id().find(&player_id) {
+                ctx.db.player().player_id().delete(&player_id);
+                ctx.db.player().try_insert(Player {
+                    player_id,
+                    display_name,
+                    created_at: existing.created_at,
+                }).map_err(|_| JoinGameError::NameTaken)?;
+            }
+            player_id
+        }
+        None => {
+            let player_id = get_next_player_id(ctx);
+            ctx.db.player().try_insert(Player {
+                player_id,
+                display_name,
+                created_at: ctx.timestamp,
+            }).map_err(|_| JoinGameError::NameTaken)?;
+            player_id
+        }
     };
-    
-    if ctx.db.player().try_insert(player).is_err() {
-        return;
+
+    if let Some(player_game) = existing_player_game {
+        // Rejoin: keep existing progress, just refresh the requested role.
+        let player_game = PlayerGame { role, ..player_game };
+        ctx.db.player_game().player_game_id().delete(&player_game.player_game_id);
+        ctx.db.player_game().try_insert(player_game).map_err(|_| JoinGameError::GameFull)?;
+    } else {
+        let player_game = PlayerGame {
+            player_game_id: get_next_player_game_id(ctx),
+            player_id,
+            game_id: game.game_id,
+            joined_at: ctx.timestamp,
+            checkpoints_scanned: 0,
+            last_scan_at: None,
+            next_required: 1,
+            finished_at: None,
+            finish_rank: None,
+            points_total: 0,
+            role,
+        };
+        ctx.db.player_game().try_insert(player_game).map_err(|_| JoinGameError::GameFull)?;
     }
 
-    let player_game = PlayerGame {
-        player_game_id: get_next_player_game_id(ctx),
+    bind_session(ctx, player_id);
+    Ok(())
+}
+
+// Issue a one-time code for the caller's player so a second device can attach
+// to the same player via `link_device`.
+#[reducer]
+pub fn create_link_code(ctx: &ReducerContext) -> Result<String, String> {
+    let player_id = resolve_player_id(ctx)?;
+    let token = generate_link_token(ctx);
+
+    ctx.db.link_code().insert(LinkCode {
+        token: token.clone(),
         player_id,
-        game_id: game.game_id,
-        joined_at: ctx.timestamp,
-        checkpoints_scanned: 0,
-        last_scan_at: None,
-        next_required: 1,
-    };
-    
-    ctx.db.player_game().try_insert(player_game).ok();
+        used: false,
+    });
+
+    Ok(token)
+}
+
+// Attach this device's identity to the player a one-time code was issued for.
+#[reducer]
+pub fn link_device(ctx: &ReducerContext, token: String) -> Result<(), String> {
+    let code = ctx.db.link_code().iter()
+        .find(|c| c.token == token && !c.used)
+        .ok_or("Invalid or already-used link code")?;
+
+    let player_id = code.player_id;
+    ctx.db.link_code().delete(code);
+    ctx.db.link_code().insert(LinkCode {
+        token,
+        player_id,
+        used: true,
+    });
+
+    bind_session(ctx, player_id);
+
+    Ok(())
+}
+
+// Subscribe `follower_player_id` (a spectator or teammate) to another
+// player's progress/position updates without being an active racer.
+#[reducer]
+pub fn follow(ctx: &ReducerContext, follower_player_id: u64, target_player_id: u64) -> Result<(), String> {
+    if follower_player_id == target_player_id {
+        return Err("cannot follow yourself".to_string());
+    }
+
+    ctx.db.player().player_id().find(&follower_player_id).ok_or("Follower not found")?;
+    ctx.db.player().player_id().find(&target_player_id).ok_or("Target not found")?;
+
+    let already_following = ctx.db.follow().iter()
+        .any(|f| f.follower_player_id == follower_player_id && f.target_player_id == target_player_id);
+    if already_following {
+        return Ok(());
+    }
+
+    ctx.db.follow().insert(Follow {
+        follow_id: get_next_follow_id(ctx),
+        follower_player_id,
+        target_player_id,
+    });
+
+    Ok(())
 }
 
 #[reducer]
 pub fn scan_checkpoint(
     ctx: &ReducerContext,
-    player_id: u64,
     game_code: String,
     nfc_uid: String,
     client_token: String,
-) {
-    let game = match ctx.db.game().iter().find(|g| g.code == game_code) {
-        Some(g) => g,
-        None => return,
-    };
-    
+) -> Result<(), String> {
+    let player_id = resolve_player_id(ctx)?;
+
+    let game = ctx.db.game().iter().find(|g| g.code == game_code)
+        .ok_or("Game not found")?;
+
     if !game.is_active {
-        return;
+        return Err("Game is not active".to_string());
+    }
+
+    if game.ends_at.map_or(false, |ends_at| ctx.timestamp >= ends_at) {
+        return Err("Game has expired".to_string());
+    }
+
+    let checkpoint = ctx.db.checkpoint().iter()
+        .find(|cp| cp.game_id == game.game_id && cp.nfc_uid == nfc_uid)
+        .ok_or("Checkpoint not found")?;
+
+    if !checkpoint.is_active {
+        return Err("Checkpoint is not active".to_string());
+    }
+
+    if checkpoint.expires_at.map_or(false, |expires_at| ctx.timestamp >= expires_at) {
+        return Err("Checkpoint has expired".to_string());
+    }
+
+    // Checkpoints with a recorded location must be scanned through
+    // `scan_checkpoint_at`, otherwise the geofence check never runs and is
+    // just opt-in.
+    if checkpoint.lat.is_some() && checkpoint.lon.is_some() {
+        return Err("this checkpoint requires a location-verified scan".to_string());
+    }
+
+    let player_game = ctx.db.player_game().iter()
+        .find(|pg| pg.player_id == player_id && pg.game_id == game.game_id)
+        .ok_or("Player has not joined this game")?;
+
+    if player_game.role == PlayerRole::Spectator {
+        return Err("Spectators cannot scan checkpoints".to_string());
+    }
+
+    // Order enforcement: Sequential games must scan checkpoints in order.
+    if game.mode == GameMode::Sequential && checkpoint.order_index != player_game.next_required {
+        return Err("Checkpoint is out of order".to_string());
     }
-    
-    let checkpoint = match ctx.db.checkpoint().iter()
-        .find(|cp| cp.game_id == game.game_id && cp.nfc_uid == nfc_uid) {
-        Some(cp) => cp,
-        None => return,
+
+    // Check if already scanned
+    let already_scanned = ctx.db.scan_event().iter()
+        .any(|se| se.game_id == game.game_id &&
+                  se.player_id == player_id &&
+                  se.checkpoint_id == checkpoint.checkpoint_id);
+
+    if already_scanned {
+        return Err("Checkpoint already scanned".to_string());
+    }
+
+    // Record the scan
+    let scan_event = ScanEvent {
+        scan_id: get_next_scan_id(ctx),
+        game_id: game.game_id,
+        player_id,
+        checkpoint_id: checkpoint.checkpoint_id,
+        scanned_at: ctx.timestamp,
+        client_token,
+        player_lat: None,
+        player_lon: None,
+        player_accuracy_m: None,
     };
-    
-    let player_game = match ctx.db.player_game().iter()
-        .find(|pg| pg.player_id == player_id && pg.game_id == game.game_id) {
-        Some(pg) => pg,
-        None => return,
+
+    ctx.db.scan_event().try_insert(scan_event).map_err(|_| "Failed to record scan".to_string())?;
+
+    // Update player progress
+    let points_total = if game.mode == GameMode::Scored {
+        player_game.points_total + checkpoint.points.unwrap_or(0)
+    } else {
+        player_game.points_total
+    };
+    let updated_player_game = PlayerGame {
+        player_game_id: player_game.player_game_id,
+        player_id: player_game.player_id,
+        game_id: player_game.game_id,
+        joined_at: player_game.joined_at,
+        checkpoints_scanned: player_game.checkpoints_scanned + 1,
+        last_scan_at: Some(ctx.timestamp),
+        next_required: player_game.next_required + 1,
+        finished_at: player_game.finished_at,
+        finish_rank: player_game.finish_rank,
+        points_total,
+        role: player_game.role,
     };
-    
-    // Order enforcement: must scan checkpoints in sequence
-    if checkpoint.order_index != player_game.next_required {
-        return;
+    let updated_player_game = mark_finished_if_complete(ctx, &updated_player_game);
+
+    ctx.db.player_game().player_game_id().delete(&player_game.player_game_id);
+    ctx.db.player_game().try_insert(updated_player_game).ok();
+
+    recompute_leaderboard(ctx, game.game_id);
+
+    Ok(())
+}
+
+// Scan a checkpoint, rejecting the scan unless the player is physically near
+// it. Checkpoints with no recorded location (lat/lon still None) skip the
+// geofence.
+#[reducer]
+pub fn scan_checkpoint_at(
+    ctx: &ReducerContext,
+    game_code: String,
+    nfc_uid: String,
+    client_token: String,
+    player_lat: f64,
+    player_lon: f64,
+    player_accuracy_m: f64,
+) -> Result<(), String> {
+    let player_id = resolve_player_id(ctx)?;
+
+    let game = ctx.db.game().iter().find(|g| g.code == game_code)
+        .ok_or("Game not found")?;
+
+    if !game.is_active {
+        return Err("Game is not active".to_string());
+    }
+
+    if game.ends_at.map_or(false, |ends_at| ctx.timestamp >= ends_at) {
+        return Err("Game has expired".to_string());
+    }
+
+    let checkpoint = ctx.db.checkpoint().iter()
+        .find(|cp| cp.game_id == game.game_id && cp.nfc_uid == nfc_uid)
+        .ok_or("Checkpoint not found")?;
+
+    if !checkpoint.is_active {
+        return Err("Checkpoint is not active".to_string());
+    }
+
+    if checkpoint.expires_at.map_or(false, |expires_at| ctx.timestamp >= expires_at) {
+        return Err("Checkpoint has expired".to_string());
+    }
+
+    let player_game = ctx.db.player_game().iter()
+        .find(|pg| pg.player_id == player_id && pg.game_id == game.game_id)
+        .ok_or("Player has not joined this game")?;
+
+    if player_game.role == PlayerRole::Spectator {
+        return Err("Spectators cannot scan checkpoints".to_string());
+    }
+
+    // Order enforcement: Sequential games must scan checkpoints in order.
+    if game.mode == GameMode::Sequential && checkpoint.order_index != player_game.next_required {
+        return Err("Checkpoint is out of order".to_string());
     }
-    
+
     // Check if already scanned
     let already_scanned = ctx.db.scan_event().iter()
         .any(|se| se.game_id == game.game_id &&
                   se.player_id == player_id &&
                   se.checkpoint_id == checkpoint.checkpoint_id);
-    
+
     if already_scanned {
-        return;
+        return Err("Checkpoint already scanned".to_string());
     }
-    
+
+    // Geofence: reject the scan unless the player is close enough to the
+    // checkpoint. Checkpoints without a recorded location have no geofence.
+    if let (Some(cp_lat), Some(cp_lon)) = (checkpoint.lat, checkpoint.lon) {
+        let distance_m = haversine_distance_m(cp_lat, cp_lon, player_lat, player_lon);
+        let cp_accuracy_m = checkpoint.accuracy_m.unwrap_or(0.0);
+        let allowed_m = cp_accuracy_m + player_accuracy_m + GEOFENCE_SLACK_M;
+
+        if distance_m > allowed_m {
+            return Err(format!("you're {}m away", distance_m.round() as i64));
+        }
+    }
+
     // Record the scan
     let scan_event = ScanEvent {
         scan_id: get_next_scan_id(ctx),
@@ -229,13 +866,19 @@ pub fn scan_checkpoint(
         checkpoint_id: checkpoint.checkpoint_id,
         scanned_at: ctx.timestamp,
         client_token,
+        player_lat: Some(player_lat),
+        player_lon: Some(player_lon),
+        player_accuracy_m: Some(player_accuracy_m),
     };
-    
-    if ctx.db.scan_event().try_insert(scan_event).is_err() {
-        return;
-    }
-    
+
+    ctx.db.scan_event().try_insert(scan_event).map_err(|_| "Failed to record scan".to_string())?;
+
     // Update player progress
+    let points_total = if game.mode == GameMode::Scored {
+        player_game.points_total + checkpoint.points.unwrap_or(0)
+    } else {
+        player_game.points_total
+    };
     let updated_player_game = PlayerGame {
         player_game_id: player_game.player_game_id,
         player_id: player_game.player_id,
@@ -244,8 +887,17 @@ pub fn scan_checkpoint(
         checkpoints_scanned: player_game.checkpoints_scanned + 1,
         last_scan_at: Some(ctx.timestamp),
         next_required: player_game.next_required + 1,
+        finished_at: player_game.finished_at,
+        finish_rank: player_game.finish_rank,
+        points_total,
+        role: player_game.role,
     };
-    
+    let updated_player_game = mark_finished_if_complete(ctx, &updated_player_game);
+
     ctx.db.player_game().player_game_id().delete(&player_game.player_game_id);
     ctx.db.player_game().try_insert(updated_player_game).ok();
+
+    recompute_leaderboard(ctx, game.game_id);
+
+    Ok(())
 }
\ No newline at end of file
@@ -1,14 +1,87 @@
-use spacetimedb::{Table, ReducerContext, table, reducer};
+use spacetimedb::{Identity, ScheduleAt, SpacetimeType, Table, ReducerContext, TimeDuration, table, reducer};
+
+// Governs how players may claim a game's checkpoints.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, SpacetimeType)]
+pub enum GameMode {
+    // Tags must be claimed in `order_index` order.
+    Sequential,
+    // Any active tag can be claimed once, in any order.
+    FreeOrder,
+    // Like FreeOrder, but each tag's `points` accumulate onto the player's
+    // leaderboard standing.
+    Scored,
+}
+
+// A player's standing in a game. Enforced on every role-gated reducer rather
+// than trusted as free-text metadata.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, SpacetimeType)]
+pub enum PlayerRole {
+    // The game's host. Can create/activate/delete tags; implied by
+    // `Game.host`, not requested through `upsert_player`.
+    Organizer,
+    // Claims tags and appears on the leaderboard.
+    Player,
+    // Can follow players' progress but cannot claim tags.
+    Spectator,
+}
 
-// Simple game table - only one active game at a time
+// Games table - keyed by game_id, supports many concurrent games like
+// rooms on a multiplayer server rather than one global game.
 #[table(name = games, public)]
+#[derive(Clone)]
 pub struct Game {
+    #[primary_key]
     game_id: String,
     status: String, // 'setup' | 'active' | 'ended'
+    // When the game's timer runs out (micros since Unix epoch). None means
+    // the game has no timer and stays active until `end_game` is called.
+    ends_at: Option<i64>,
+    // The identity that created the game. Only the host may mutate this
+    // game's setup (tags, start/end).
+    host: Identity,
+    // Caps how many players may join this game. None means unlimited.
+    max_players: Option<u32>,
+    mode: GameMode,
+}
+
+// Typed so a client can branch on why upsert_player failed instead of
+// pattern-matching an opaque error string.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JoinGameError {
+    GameNotFound,
+    GameNotActive,
+    GameFull,
+    NameTaken,
+    // `player_id` is already bound (in `session`) to a different caller
+    // identity. Prevents an attacker from supplying a victim's player_id to
+    // hijack their session.
+    PlayerIdTaken,
+}
+
+impl std::fmt::Display for JoinGameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JoinGameError::GameNotFound => write!(f, "game not found"),
+            JoinGameError::GameNotActive => write!(f, "game is not active"),
+            JoinGameError::GameFull => write!(f, "game is full"),
+            JoinGameError::NameTaken => write!(f, "name already taken"),
+            JoinGameError::PlayerIdTaken => write!(f, "player id belongs to another session"),
+        }
+    }
+}
+
+// Only the game's host may perform organizer-only actions like activating
+// tags or starting/ending the game.
+fn require_organizer(ctx: &ReducerContext, game: &Game) -> Result<(), String> {
+    if game.host != ctx.sender {
+        return Err("not organizer".to_string());
+    }
+    Ok(())
 }
 
 // Simple tags table with order_index for sequential claiming
 #[table(name = tags, public)]
+#[derive(Clone)]
 pub struct Tag {
     #[primary_key]
     tag_id: String,
@@ -22,13 +95,97 @@ pub struct Tag {
     accuracy_m: Option<i32>,
     activated_by: Option<String>,
     activated_at: Option<i64>,
+    // When this tag's activation window runs out (micros since Unix epoch).
+    // None means the tag stays active until explicitly deactivated/deleted.
+    expires_at: Option<i64>,
+    // Points awarded for claiming this tag. Only meaningful in `Scored` games.
+    points: Option<i32>,
+}
+
+// How often the scheduled reducer sweeps for expired games/tags.
+const TICK_INTERVAL_SECS: u64 = 10;
+
+// Runs every `TICK_INTERVAL_SECS` seconds to end timed-out games and
+// deactivate timed-out tags, so hunts expire without an external cron.
+#[table(name = scheduled_task, scheduled(expire_tick))]
+pub struct ScheduledTask {
+    #[primary_key]
+    #[auto_inc]
+    scheduled_id: u64,
+    scheduled_at: ScheduleAt,
+}
+
+#[reducer(init)]
+pub fn init(ctx: &ReducerContext) {
+    ctx.db.scheduled_task().insert(ScheduledTask {
+        scheduled_id: 0,
+        scheduled_at: TimeDuration::from_duration(std::time::Duration::from_secs(TICK_INTERVAL_SECS)).into(),
+    });
+}
+
+#[reducer]
+pub fn expire_tick(ctx: &ReducerContext, _task: ScheduledTask) -> Result<(), String> {
+    // `ctx.sender` is the module's own identity only when SpacetimeDB itself
+    // invoked this on schedule; reject anyone else calling it directly.
+    if ctx.sender != ctx.identity() {
+        return Err("expire_tick may only be invoked by the scheduler".to_string());
+    }
+
+    let now = ctx.timestamp.to_micros_since_unix_epoch();
+
+    let expired_games: Vec<Game> = ctx.db.games().iter()
+        .filter(|g| g.status == "active" && g.ends_at.map_or(false, |ends_at| now >= ends_at))
+        .collect();
+
+    for game in expired_games {
+        ctx.db.games().delete(game.clone());
+        ctx.db.games().insert(Game { status: "ended".to_string(), ..game });
+    }
+
+    let expired_tags: Vec<Tag> = ctx.db.tags().iter()
+        .filter(|t| t.is_active && t.expires_at.map_or(false, |expires_at| now >= expires_at))
+        .collect();
+
+    for tag in expired_tags {
+        let tag_id = tag.tag_id.clone();
+        ctx.db.tags().delete(tag.clone());
+        ctx.db.tags().insert(Tag { is_active: false, ..tag });
+        log::info!("Deactivated expired tag: {}", tag_id);
+    }
+
+    Ok(())
 }
 
-// Simple players table
+// Simple players table. Scoped by game_id so names only need to be unique
+// within a given game, not globally.
 #[table(name = players, public)]
+#[derive(Clone)]
 pub struct Player {
     player_id: String,
+    game_id: String,
     name: String,
+    role: PlayerRole,
+}
+
+// Maps a caller's identity to the player_id it's playing as, so reducers
+// resolve the acting player from ctx.sender rather than a client-supplied
+// player_id (which upsert_player's own String player_id would otherwise let
+// a caller spoof).
+#[table(name = session, public)]
+pub struct Session {
+    #[primary_key]
+    identity: Identity,
+    player_id: String,
+}
+
+// One-time codes handed out by `create_link_code` so a second device can bind
+// to an already-registered player via `link_device`.
+#[table(name = link_code, public)]
+pub struct LinkCode {
+    #[primary_key]
+    token: String,
+    player_id: String,
+    used: bool,
 }
 
 // Simple progress table - tracks who claimed what
@@ -39,72 +196,263 @@ pub struct Progress {
     tag_id: String,
     order_index: i32,
     timestamp: i64,
+    // Reported position at claim time, kept for anti-cheat audit.
+    player_lat: Option<f64>,
+    player_lon: Option<f64>,
+    player_accuracy_m: Option<i32>,
 }
 
-// Create a new game (wipes everything)
-#[reducer]
-pub fn create_game(ctx: &ReducerContext, game_id: String) -> Result<(), String> {
-    // Delete all existing data
-    let games_count = ctx.db.games().iter().count();
-    let tags_count = ctx.db.tags().iter().count();
-    let players_count = ctx.db.players().iter().count();
-    let progress_count = ctx.db.progress().iter().count();
-    
-    log::info!("Wiping database: {} games, {} tags, {} players, {} progress entries", 
-               games_count, tags_count, players_count, progress_count);
-    
-    for game in ctx.db.games().iter() {
-        ctx.db.games().delete(game);
+// Ranks each game's players by tags claimed (by points_total in `Scored`
+// games), tie-broken by whoever claimed last earlier, recomputed after every claim so clients can subscribe to
+// live standings. Keyed by "{game_id}:{player_id}" since a player only has
+// one standing per game.
+#[table(name = leaderboard, public)]
+pub struct LeaderboardEntry {
+    #[primary_key]
+    entry_id: String,
+    game_id: String,
+    player_id: String,
+    rank: u32,
+    tags_claimed: u32,
+    last_claim_at: Option<i64>,
+    // Sum of claimed tags' `points`. Only meaningful in `Scored` games.
+    points_total: u32,
+    // Set the first time this player has claimed every tag in the game.
+    finished_at: Option<i64>,
+    // 1-based placement among players who have finished this game.
+    finish_rank: Option<u32>,
+}
+
+// Lets a spectator or teammate subscribe to another player's progress
+// without being an active racer themselves.
+#[table(name = follow, public)]
+pub struct Follow {
+    #[primary_key]
+    follow_id: String, // "{follower_player_id}:{target_player_id}"
+    follower_player_id: String,
+    target_player_id: String,
+}
+
+// Extra slack added on top of the tag's and player's reported GPS accuracy
+// before a claim is rejected as "too far away".
+const GEOFENCE_SLACK_M: f64 = 15.0;
+
+// Great-circle distance between two lat/lon points, in meters.
+fn haversine_distance_m(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    const EARTH_RADIUS_M: f64 = 6_371_000.0;
+    let phi1 = lat1.to_radians();
+    let phi2 = lat2.to_radians();
+    let delta_phi = (lat2 - lat1).to_radians();
+    let delta_lambda = (lon2 - lon1).to_radians();
+
+    let a = (delta_phi / 2.0).sin().powi(2)
+        + phi1.cos() * phi2.cos() * (delta_lambda / 2.0).sin().powi(2);
+    let c = 2.0 * a.sqrt().atan2((1.0 - a).sqrt());
+
+    EARTH_RADIUS_M * c
+}
+
+#[cfg(test)]
+mod haversine_tests {
+    use super::haversine_distance_m;
+
+    #[test]
+    fn same_point_is_zero_distance() {
+        assert_eq!(haversine_distance_m(40.0, -75.0, 40.0, -75.0), 0.0);
     }
-    for tag in ctx.db.tags().iter() {
-        ctx.db.tags().delete(tag);
+
+    #[test]
+    fn one_degree_of_latitude_is_about_111km() {
+        let distance_m = haversine_distance_m(0.0, 0.0, 1.0, 0.0);
+        assert!((distance_m - 111_195.0).abs() < 1_000.0, "got {distance_m}");
     }
-    for player in ctx.db.players().iter() {
-        ctx.db.players().delete(player);
+
+    #[test]
+    fn is_symmetric() {
+        let a_to_b = haversine_distance_m(40.0, -75.0, 41.0, -74.0);
+        let b_to_a = haversine_distance_m(41.0, -74.0, 40.0, -75.0);
+        assert_eq!(a_to_b, b_to_a);
     }
-    for progress in ctx.db.progress().iter() {
-        ctx.db.progress().delete(progress);
+}
+
+// Finished once they've claimed every tag in the game. Valid in all modes,
+// unlike "claimed the highest order_index tag" which only implies
+// completion when claiming is Sequential.
+fn has_claimed_all_tags(tags_claimed: u32, total_tags: u32) -> bool {
+    total_tags > 0 && tags_claimed >= total_tags
+}
+
+// Recompute the leaderboard for a game: rank by tags claimed descending
+// (points_total in `Scored` games), ties broken by whoever last claimed
+// earlier. Finish stamps, once set, are preserved across recomputes rather
+// than overwritten.
+fn recompute_leaderboard(ctx: &ReducerContext, game_id: &str) {
+    let game_mode = ctx.db.games().iter().find(|g| g.game_id == game_id).map(|g| g.mode);
+
+    let tags: Vec<Tag> = ctx.db.tags().iter().filter(|t| t.game_id == game_id).collect();
+    let total_tags = tags.len() as u32;
+
+    let all_progress: Vec<Progress> = ctx.db.progress().iter()
+        .filter(|p| p.game_id == game_id)
+        .collect();
+
+    let mut player_ids: Vec<String> = all_progress.iter().map(|p| p.player_id.clone()).collect();
+    player_ids.sort();
+    player_ids.dedup();
+
+    let mut standings: Vec<(String, u32, Option<i64>, u32, bool)> = player_ids.into_iter().map(|player_id| {
+        let claims: Vec<&Progress> = all_progress.iter().filter(|p| p.player_id == player_id).collect();
+        let tags_claimed = claims.len() as u32;
+        let last_claim_at = claims.iter().map(|p| p.timestamp).max();
+        let points_total = if game_mode == Some(GameMode::Scored) {
+            claims.iter()
+                .filter_map(|p| tags.iter().find(|t| t.tag_id == p.tag_id))
+                .filter_map(|t| t.points)
+                .map(|points| points.max(0) as u32)
+                .sum()
+        } else {
+            0
+        };
+        let completed_final = has_claimed_all_tags(tags_claimed, total_tags);
+        (player_id, tags_claimed, last_claim_at, points_total, completed_final)
+    }).collect();
+
+    // Scored games rank by points, not raw tag count, so point values
+    // actually affect standings.
+    if game_mode == Some(GameMode::Scored) {
+        standings.sort_by(|a, b| b.3.cmp(&a.3).then_with(|| a.2.cmp(&b.2)));
+    } else {
+        standings.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.2.cmp(&b.2)));
+    }
+
+    let mut finished_so_far = ctx.db.leaderboard().iter()
+        .filter(|e| e.game_id == game_id && e.finished_at.is_some())
+        .count() as u32;
+
+    for (i, (player_id, tags_claimed, last_claim_at, points_total, completed_final)) in standings.into_iter().enumerate() {
+        let entry_id = format!("{}:{}", game_id, player_id);
+        let existing = ctx.db.leaderboard().entry_id().find(&entry_id);
+
+        let (finished_at, finish_rank) = match &existing {
+            Some(e) if e.finished_at.is_some() => (e.finished_at, e.finish_rank),
+            _ if completed_final => {
+                finished_so_far += 1;
+                (Some(ctx.timestamp.to_micros_since_unix_epoch()), Some(finished_so_far))
+            }
+            _ => (None, None),
+        };
+
+        if existing.is_some() {
+            ctx.db.leaderboard().entry_id().delete(&entry_id);
+        }
+
+        ctx.db.leaderboard().insert(LeaderboardEntry {
+            entry_id,
+            game_id: game_id.to_string(),
+            player_id,
+            rank: i as u32 + 1,
+            tags_claimed,
+            last_claim_at,
+            points_total,
+            finished_at,
+            finish_rank,
+        });
+    }
+}
+
+#[cfg(test)]
+mod finish_tests {
+    use super::has_claimed_all_tags;
+
+    #[test]
+    fn not_finished_before_claiming_every_tag() {
+        assert!(!has_claimed_all_tags(2, 3));
+    }
+
+    #[test]
+    fn finished_after_claiming_every_tag() {
+        assert!(has_claimed_all_tags(3, 3));
+    }
+
+    #[test]
+    fn game_with_no_tags_has_nothing_to_finish() {
+        assert!(!has_claimed_all_tags(0, 0));
+    }
+}
+
+// Resolve the calling identity to the player_id it's bound to via `session`.
+fn resolve_player_id(ctx: &ReducerContext) -> Result<String, String> {
+    ctx.db.session().iter()
+        .find(|s| s.identity == ctx.sender)
+        .map(|s| s.player_id)
+        .ok_or_else(|| "not joined".to_string())
+}
+
+// Bind the caller's identity to `player_id`, replacing any prior binding.
+fn bind_session(ctx: &ReducerContext, player_id: String) {
+    if let Some(existing) = ctx.db.session().iter().find(|s| s.identity == ctx.sender) {
+        ctx.db.session().delete(existing);
+    }
+    ctx.db.session().insert(Session {
+        identity: ctx.sender,
+        player_id,
+    });
+}
+
+// Create a new game room. The caller becomes the host/organizer. Does not
+// touch any other game - multiple games can run concurrently, each keyed by
+// its own game_id.
+#[reducer]
+pub fn create_game(ctx: &ReducerContext, game_id: String, max_players: Option<u32>, mode: GameMode) -> Result<(), String> {
+    if ctx.db.games().iter().any(|g| g.game_id == game_id) {
+        return Err(format!("Game {} already exists", game_id));
     }
 
-    // Create new game
     ctx.db.games().insert(Game {
         game_id: game_id.clone(),
         status: "setup".to_string(),
+        ends_at: None,
+        host: ctx.sender,
+        max_players,
+        mode,
     });
 
-    log::info!("Created new game: {} (database wiped clean)", game_id);
+    log::info!("Created new game: {}", game_id);
     Ok(())
 }
 
-// Start the game
+// Start the game (organizer only). `duration_secs` is optional - omit it for
+// a game with no timer, which stays active until `end_game` is called
+// explicitly.
 #[reducer]
-pub fn start_game(ctx: &ReducerContext, game_id: String) -> Result<(), String> {
+pub fn start_game(ctx: &ReducerContext, game_id: String, duration_secs: Option<u64>) -> Result<(), String> {
     let game = ctx.db.games().iter()
         .find(|g| g.game_id == game_id)
         .ok_or("Game not found")?;
 
-    ctx.db.games().delete(game);
-    ctx.db.games().insert(Game {
-        game_id: game_id.clone(),
-        status: "active".to_string(),
-    });
+    require_organizer(ctx, &game)?;
+
+    let now = ctx.timestamp.to_micros_since_unix_epoch();
+    let ends_at = duration_secs.map(|secs| now + secs as i64 * 1_000_000);
+
+    ctx.db.games().delete(game.clone());
+    ctx.db.games().insert(Game { status: "active".to_string(), ends_at, ..game });
 
-    log::info!("Started game: {}", game_id);
+    log::info!("Started game: {} (ends_at: {:?})", game_id, ends_at);
     Ok(())
 }
 
-// End the game
+// End the game (organizer only)
 #[reducer]
 pub fn end_game(ctx: &ReducerContext, game_id: String) -> Result<(), String> {
     let game = ctx.db.games().iter()
         .find(|g| g.game_id == game_id)
         .ok_or("Game not found")?;
 
-    ctx.db.games().delete(game);
-    ctx.db.games().insert(Game {
-        game_id: game_id.clone(),
-        status: "ended".to_string(),
-    });
+    require_organizer(ctx, &game)?;
+
+    ctx.db.games().delete(game.clone());
+    ctx.db.games().insert(Game { status: "ended".to_string(), ..game });
 
     log::info!("Ended game: {}", game_id);
     Ok(())
@@ -112,12 +460,14 @@ pub fn end_game(ctx: &ReducerContext, game_id: String) -> Result<(), String> {
 
 // Create a tag (organizer only) - creates inactive tag
 #[reducer]
-pub fn create_tag(ctx: &ReducerContext, game_id: String, tag_id: String, order_index: i32, clue: Option<String>) -> Result<(), String> {
+pub fn create_tag(ctx: &ReducerContext, game_id: String, tag_id: String, order_index: i32, clue: Option<String>, points: Option<i32>) -> Result<(), String> {
     // Check if game exists
-    let _game = ctx.db.games().iter()
+    let game = ctx.db.games().iter()
         .find(|g| g.game_id == game_id)
         .ok_or("Game not found")?;
 
+    require_organizer(ctx, &game)?;
+
     // Check if tag already exists (globally, not just in this game)
     if let Some(_existing_tag) = ctx.db.tags().iter()
         .find(|t| t.tag_id == tag_id) {
@@ -136,20 +486,25 @@ pub fn create_tag(ctx: &ReducerContext, game_id: String, tag_id: String, order_i
         accuracy_m: None,
         activated_by: None,
         activated_at: None,
+        expires_at: None,
+        points,
     });
 
     log::info!("Created tag: {} in game: {}", tag_id, game_id);
     Ok(())
 }
 
-// Activate a tag (organizer only)
+// Activate a tag (organizer only). `active_secs` is optional - omit it for a
+// tag that stays active until explicitly deactivated/deleted.
 #[reducer]
-pub fn activate_tag(ctx: &ReducerContext, game_id: String, tag_id: String, order_index: i32, clue: Option<String>) -> Result<(), String> {
+pub fn activate_tag(ctx: &ReducerContext, game_id: String, tag_id: String, order_index: i32, clue: Option<String>, active_secs: Option<u64>, points: Option<i32>) -> Result<(), String> {
     // Check if game exists
-    let _game = ctx.db.games().iter()
+    let game = ctx.db.games().iter()
         .find(|g| g.game_id == game_id)
         .ok_or("Game not found")?;
 
+    require_organizer(ctx, &game)?;
+
     // Find and delete existing tag if it exists (globally)
     if let Some(existing_tag) = ctx.db.tags().iter()
         .find(|t| t.tag_id == tag_id) {
@@ -157,6 +512,9 @@ pub fn activate_tag(ctx: &ReducerContext, game_id: String, tag_id: String, order
         log::info!("Deleted existing tag: {} before activating", tag_id);
     }
 
+    let now = ctx.timestamp.to_micros_since_unix_epoch();
+    let expires_at = active_secs.map(|secs| now + secs as i64 * 1_000_000);
+
     // Create new tag as active
     ctx.db.tags().insert(Tag {
         tag_id: tag_id.clone(),
@@ -169,20 +527,26 @@ pub fn activate_tag(ctx: &ReducerContext, game_id: String, tag_id: String, order
         accuracy_m: None,
         activated_by: None,
         activated_at: None,
+        expires_at,
+        points,
     });
 
     log::info!("Activated tag: {} in game: {}", tag_id, game_id);
     Ok(())
 }
 
-// Activate a tag with geolocation (organizer only)
+// Activate a tag with geolocation (organizer only). `active_secs` is
+// optional - omit it for a tag that stays active until explicitly
+// deactivated/deleted.
 #[reducer]
-pub fn activate_tag_with_location(ctx: &ReducerContext, game_id: String, tag_id: String, lat: f64, lon: f64, accuracy_m: i32, activated_by: String, order_index: i32, clue: Option<String>) -> Result<(), String> {
+pub fn activate_tag_with_location(ctx: &ReducerContext, game_id: String, tag_id: String, lat: f64, lon: f64, accuracy_m: i32, activated_by: String, order_index: i32, clue: Option<String>, active_secs: Option<u64>, points: Option<i32>) -> Result<(), String> {
     // Check if game exists
-    let _game = ctx.db.games().iter()
+    let game = ctx.db.games().iter()
         .find(|g| g.game_id == game_id)
         .ok_or("Game not found")?;
 
+    require_organizer(ctx, &game)?;
+
     // Find and delete existing tag if it exists (globally)
     if let Some(existing_tag) = ctx.db.tags().iter()
         .find(|t| t.tag_id == tag_id) {
@@ -190,6 +554,9 @@ pub fn activate_tag_with_location(ctx: &ReducerContext, game_id: String, tag_id:
         log::info!("Deleted existing tag: {} before activating with location", tag_id);
     }
 
+    let now = ctx.timestamp.to_micros_since_unix_epoch();
+    let expires_at = active_secs.map(|secs| now + secs as i64 * 1_000_000);
+
     // Create new tag as active with geolocation
     ctx.db.tags().insert(Tag {
         tag_id: tag_id.clone(),
@@ -202,16 +569,20 @@ pub fn activate_tag_with_location(ctx: &ReducerContext, game_id: String, tag_id:
         accuracy_m: Some(accuracy_m),
         activated_by: Some(activated_by),
         activated_at: None, // TODO: Use proper timestamp when available
+        expires_at,
+        points,
     });
 
-    log::info!("Activated tag: {} in game: {} with location: {:.5}, {:.5} (Â±{}m), order: {}, clue: {:?}", 
+    log::info!("Activated tag: {} in game: {} with location: {:.5}, {:.5} (Â±{}m), order: {}, clue: {:?}",
                tag_id, game_id, lat, lon, accuracy_m, order_index, clue);
     Ok(())
 }
 
 // Claim a tag (players only) - must be in order
 #[reducer]
-pub fn claim_tag(ctx: &ReducerContext, game_id: String, player_id: String, tag_id: String) -> Result<(), String> {
+pub fn claim_tag(ctx: &ReducerContext, game_id: String, tag_id: String) -> Result<(), String> {
+    let player_id = resolve_player_id(ctx)?;
+
     // Check if game exists and is active
     let game = ctx.db.games().iter()
         .find(|g| g.game_id == game_id)
@@ -221,15 +592,30 @@ pub fn claim_tag(ctx: &ReducerContext, game_id: String, player_id: String, tag_i
         return Err("Game is not active".to_string());
     }
 
+    let now = ctx.timestamp.to_micros_since_unix_epoch();
+    if game.ends_at.map_or(false, |ends_at| now >= ends_at) {
+        return Err("Game has expired".to_string());
+    }
+
     // Check if tag exists and is active
     let tag = ctx.db.tags().iter()
         .find(|t| t.tag_id == tag_id && t.game_id == game_id)
         .ok_or("Tag not found")?;
-    
+
     if !tag.is_active {
         return Err("Tag is not active".to_string());
     }
 
+    if tag.expires_at.map_or(false, |expires_at| now >= expires_at) {
+        return Err("Tag has expired".to_string());
+    }
+
+    // Tags with a recorded location must be claimed through `claim_tag_at`,
+    // otherwise the geofence check never runs and is just opt-in.
+    if tag.lat.is_some() && tag.lon.is_some() {
+        return Err("this tag requires a location-verified claim".to_string());
+    }
+
     // Check if player has already claimed this tag
     let existing_progress = ctx.db.progress().iter()
         .find(|p| p.game_id == game_id && p.player_id == player_id && p.tag_id == tag_id);
@@ -238,17 +624,28 @@ pub fn claim_tag(ctx: &ReducerContext, game_id: String, player_id: String, tag_i
         return Ok(()); // Already claimed, ignore
     }
 
-    // Check if player can claim this tag (must have claimed all previous tags)
-    let player_progress: Vec<_> = ctx.db.progress().iter()
-        .filter(|p| p.game_id == game_id && p.player_id == player_id)
-        .collect();
+    let player = ctx.db.players().iter()
+        .find(|p| p.game_id == game_id && p.player_id == player_id)
+        .ok_or("Player has not joined this game")?;
 
-    // Check if they've claimed all tags with order_index < current tag's order_index
-    for i in 1..tag.order_index {
-        let has_claimed = player_progress.iter()
-            .any(|p| p.order_index == i);
-        if !has_claimed {
-            return Err(format!("You must claim tag with order {} first", i));
+    if player.role == PlayerRole::Spectator {
+        return Err("Spectators cannot claim tags".to_string());
+    }
+
+    // Sequential games must claim tags in order; FreeOrder and Scored games
+    // allow claiming any active tag.
+    if game.mode == GameMode::Sequential {
+        let player_progress: Vec<_> = ctx.db.progress().iter()
+            .filter(|p| p.game_id == game_id && p.player_id == player_id)
+            .collect();
+
+        // Check if they've claimed all tags with order_index < current tag's order_index
+        for i in 1..tag.order_index {
+            let has_claimed = player_progress.iter()
+                .any(|p| p.order_index == i);
+            if !has_claimed {
+                return Err(format!("You must claim tag with order {} first", i));
+            }
         }
     }
 
@@ -258,18 +655,131 @@ pub fn claim_tag(ctx: &ReducerContext, game_id: String, player_id: String, tag_i
         player_id: player_id.clone(),
         tag_id: tag_id.clone(),
         order_index: tag.order_index,
-        timestamp: 0, // Simple timestamp
+        timestamp: now,
+        player_lat: None,
+        player_lon: None,
+        player_accuracy_m: None,
     });
 
+    recompute_leaderboard(ctx, &game_id);
+
     log::info!("Player {} claimed tag: {} in game: {}", player_id, tag_id, game_id);
     Ok(())
 }
 
+// Claim a tag, rejecting the claim unless the player is physically near it.
+// Tags with no recorded location (lat/lon still None) skip the geofence.
+#[reducer]
+pub fn claim_tag_at(
+    ctx: &ReducerContext,
+    game_id: String,
+    tag_id: String,
+    player_lat: f64,
+    player_lon: f64,
+    player_accuracy_m: i32,
+) -> Result<(), String> {
+    let player_id = resolve_player_id(ctx)?;
+
+    // Check if game exists and is active
+    let game = ctx.db.games().iter()
+        .find(|g| g.game_id == game_id)
+        .ok_or("Game not found")?;
+
+    if game.status != "active" {
+        return Err("Game is not active".to_string());
+    }
+
+    let now = ctx.timestamp.to_micros_since_unix_epoch();
+    if game.ends_at.map_or(false, |ends_at| now >= ends_at) {
+        return Err("Game has expired".to_string());
+    }
+
+    // Check if tag exists and is active
+    let tag = ctx.db.tags().iter()
+        .find(|t| t.tag_id == tag_id && t.game_id == game_id)
+        .ok_or("Tag not found")?;
+
+    if !tag.is_active {
+        return Err("Tag is not active".to_string());
+    }
+
+    if tag.expires_at.map_or(false, |expires_at| now >= expires_at) {
+        return Err("Tag has expired".to_string());
+    }
+
+    // Check if player has already claimed this tag
+    let existing_progress = ctx.db.progress().iter()
+        .find(|p| p.game_id == game_id && p.player_id == player_id && p.tag_id == tag_id);
+
+    if existing_progress.is_some() {
+        return Ok(()); // Already claimed, ignore
+    }
+
+    let player = ctx.db.players().iter()
+        .find(|p| p.game_id == game_id && p.player_id == player_id)
+        .ok_or("Player has not joined this game")?;
+
+    if player.role == PlayerRole::Spectator {
+        return Err("Spectators cannot claim tags".to_string());
+    }
+
+    // Sequential games must claim tags in order; FreeOrder and Scored games
+    // allow claiming any active tag.
+    if game.mode == GameMode::Sequential {
+        let player_progress: Vec<_> = ctx.db.progress().iter()
+            .filter(|p| p.game_id == game_id && p.player_id == player_id)
+            .collect();
+
+        // Check if they've claimed all tags with order_index < current tag's order_index
+        for i in 1..tag.order_index {
+            let has_claimed = player_progress.iter()
+                .any(|p| p.order_index == i);
+            if !has_claimed {
+                return Err(format!("You must claim tag with order {} first", i));
+            }
+        }
+    }
+
+    // Geofence: reject the claim unless the player is close enough to the tag.
+    // Tags without a recorded location have no geofence to enforce.
+    if let (Some(tag_lat), Some(tag_lon)) = (tag.lat, tag.lon) {
+        let distance_m = haversine_distance_m(tag_lat, tag_lon, player_lat, player_lon);
+        let tag_accuracy_m = tag.accuracy_m.unwrap_or(0) as f64;
+        let allowed_m = tag_accuracy_m + player_accuracy_m as f64 + GEOFENCE_SLACK_M;
+
+        if distance_m > allowed_m {
+            return Err(format!("you're {}m away", distance_m.round() as i64));
+        }
+    }
+
+    // Record the claim
+    ctx.db.progress().insert(Progress {
+        game_id: game_id.clone(),
+        player_id: player_id.clone(),
+        tag_id: tag_id.clone(),
+        order_index: tag.order_index,
+        timestamp: now,
+        player_lat: Some(player_lat),
+        player_lon: Some(player_lon),
+        player_accuracy_m: Some(player_accuracy_m),
+    });
+
+    recompute_leaderboard(ctx, &game_id);
+
+    log::info!("Player {} claimed tag: {} in game: {} at {:.5},{:.5}", player_id, tag_id, game_id, player_lat, player_lon);
+    Ok(())
+}
+
 // Delete a tag (organizer only)
 #[reducer]
 pub fn delete_tag(ctx: &ReducerContext, tag_id: String) -> Result<(), String> {
     // Find the tag to delete
     if let Some(tag) = ctx.db.tags().iter().find(|t| t.tag_id == tag_id) {
+        let game = ctx.db.games().iter()
+            .find(|g| g.game_id == tag.game_id)
+            .ok_or("Game not found")?;
+        require_organizer(ctx, &game)?;
+
         // Delete all progress entries for this tag
         let progress_entries: Vec<_> = ctx.db.progress().iter()
             .filter(|p| p.tag_id == tag_id)
@@ -290,14 +800,128 @@ pub fn delete_tag(ctx: &ReducerContext, tag_id: String) -> Result<(), String> {
     Ok(())
 }
 
-// Register a player
+// Register a player in a game room and bind the caller's identity to it.
 #[reducer]
-pub fn upsert_player(ctx: &ReducerContext, player_id: String, name: String, role: Option<String>) -> Result<(), String> {
+pub fn upsert_player(ctx: &ReducerContext, game_id: String, player_id: String, name: String, role: Option<PlayerRole>) -> Result<(), JoinGameError> {
+    let game = ctx.db.games().iter()
+        .find(|g| g.game_id == game_id)
+        .ok_or(JoinGameError::GameNotFound)?;
+
+    if game.status == "ended" {
+        return Err(JoinGameError::GameNotActive);
+    }
+
+    // Only `Game.host` is an organizer; a caller can't grant themselves that
+    // role through upsert_player, so downgrade the request to Player.
+    let role = match role {
+        Some(PlayerRole::Organizer) | None => PlayerRole::Player,
+        Some(other) => other,
+    };
+
+    // `session` binds player_id globally (across games), so an attacker
+    // supplying another caller's player_id would otherwise hijack their
+    // session the moment we call `bind_session` below. Only the identity a
+    // player_id is already bound to (or an as-yet-unbound id) may upsert it.
+    if let Some(bound) = ctx.db.session().iter().find(|s| s.player_id == player_id) {
+        if bound.identity != ctx.sender {
+            return Err(JoinGameError::PlayerIdTaken);
+        }
+    }
+
+    let existing = ctx.db.players().iter().find(|p| p.game_id == game_id && p.player_id == player_id);
+    let is_rejoin = existing.is_some();
+
+    let name_taken = ctx.db.players().iter()
+        .any(|p| p.game_id == game_id && p.player_id != player_id && p.name == name);
+    if name_taken {
+        return Err(JoinGameError::NameTaken);
+    }
+
+    if !is_rejoin {
+        if let Some(max_players) = game.max_players {
+            let current_players = ctx.db.players().iter().filter(|p| p.game_id == game_id).count() as u32;
+            if current_players >= max_players {
+                return Err(JoinGameError::GameFull);
+            }
+        }
+    }
+
+    if let Some(existing) = existing {
+        ctx.db.players().delete(existing);
+    }
+
     ctx.db.players().insert(Player {
         player_id: player_id.clone(),
+        game_id: game_id.clone(),
         name: name.clone(),
+        role,
+    });
+
+    bind_session(ctx, player_id.clone());
+
+    log::info!("Registered player: {} ({}) in game {} with role: {:?}", name, player_id, game_id, role);
+    Ok(())
+}
+
+// Issue a one-time code for the caller's player so a second device can attach
+// to the same player via `link_device`.
+#[reducer]
+pub fn create_link_code(ctx: &ReducerContext) -> Result<String, String> {
+    let player_id = resolve_player_id(ctx)?;
+    let token = format!("LINK{:06}", ctx.db.link_code().iter().count() + 1);
+
+    ctx.db.link_code().insert(LinkCode {
+        token: token.clone(),
+        player_id,
+        used: false,
+    });
+
+    Ok(token)
+}
+
+// Attach this device's identity to the player a one-time code was issued for.
+#[reducer]
+pub fn link_device(ctx: &ReducerContext, token: String) -> Result<(), String> {
+    let code = ctx.db.link_code().iter()
+        .find(|c| c.token == token && !c.used)
+        .ok_or("Invalid or already-used link code")?;
+
+    let player_id = code.player_id.clone();
+    let token = code.token.clone();
+    ctx.db.link_code().delete(code);
+    ctx.db.link_code().insert(LinkCode {
+        token,
+        player_id: player_id.clone(),
+        used: true,
+    });
+
+    bind_session(ctx, player_id.clone());
+
+    log::info!("Linked device to player: {}", player_id);
+    Ok(())
+}
+
+// Subscribe a spectator or teammate (follower_player_id) to another player's
+// progress/position updates without being an active racer themselves.
+#[reducer]
+pub fn follow(ctx: &ReducerContext, follower_player_id: String, target_player_id: String) -> Result<(), String> {
+    if follower_player_id == target_player_id {
+        return Err("cannot follow yourself".to_string());
+    }
+
+    ctx.db.players().iter().find(|p| p.player_id == follower_player_id).ok_or("Follower not found")?;
+    ctx.db.players().iter().find(|p| p.player_id == target_player_id).ok_or("Target not found")?;
+
+    let follow_id = format!("{}:{}", follower_player_id, target_player_id);
+    if ctx.db.follow().iter().any(|f| f.follow_id == follow_id) {
+        return Ok(());
+    }
+
+    ctx.db.follow().insert(Follow {
+        follow_id,
+        follower_player_id,
+        target_player_id,
     });
 
-    log::info!("Registered player: {} ({}) with role: {:?}", name, player_id, role);
     Ok(())
 }
\ No newline at end of file